@@ -0,0 +1,198 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use pnet::util::MacAddr;
+use serde::{Deserialize, Serialize};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::common;
+use crate::layer4::{Status, WolMessage};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct ApiConfig {
+    pub listen_on: Option<SocketAddr>,
+    pub connect_to: Option<SocketAddr>,
+}
+
+#[derive(Deserialize)]
+struct WakeRequest {
+    mac: String,
+    /// Accepted for forward compatibility; the relay currently always wakes
+    /// onto the statically configured `--l4-relay-to` networks.
+    #[serde(default)]
+    #[allow(dead_code)]
+    networks: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    rate_limited: Vec<String>,
+}
+
+/// Starts the HTTP control API. With `--api-listen` it serves `POST /wake`
+/// and `GET /status` directly; with `--api-connect` it instead dials out to a
+/// central coordinator and serves the same requests over that persistent
+/// outbound connection (PTTH-style reverse connect), so a relay behind NAT
+/// can be woken without inbound port-forwarding.
+pub fn api_worker(cfg: ApiConfig, token: CancellationToken, l4_tx: mpsc::Sender<WolMessage>, status: Arc<Status>) -> Result<JoinSet<()>> {
+    let mut tasks = JoinSet::new();
+
+    match (cfg.listen_on, cfg.connect_to) {
+        (None, None) => return Err(anyhow!("api requires either --api-listen or --api-connect")),
+        (Some(addr), _) => { tasks.spawn(listen_loop(addr, token, l4_tx, status)); },
+        (None, Some(addr)) => { tasks.spawn(reverse_connect_loop(addr, token, l4_tx, status)); },
+    }
+
+    Ok(tasks)
+}
+
+async fn listen_loop(addr: SocketAddr, token: CancellationToken, l4_tx: mpsc::Sender<WolMessage>, status: Arc<Status>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => { log::error!("unable to bind api socket: {}", e); return; }
+    };
+    log::info!("api listening on {}", addr);
+
+    loop {
+        if token.is_cancelled() { log::trace!("[api][listener] exit"); break; }
+
+        let (stream, peer) = match tokio::time::timeout(Duration::from_millis(200), listener.accept()).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => { log::warn!("api accept error: {}", e); continue; },
+            Err(_) => continue,
+        };
+
+        let l4_tx = l4_tx.clone();
+        let status = status.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &l4_tx, &status, false).await {
+                log::debug!("[api] connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Reverse-connect (PTTH-style) mode: instead of accepting inbound connections,
+/// dial out to a coordinator and hold that outbound socket open, serving
+/// wake commands off it until it drops, then reconnecting with a fixed delay.
+async fn reverse_connect_loop(addr: SocketAddr, token: CancellationToken, l4_tx: mpsc::Sender<WolMessage>, status: Arc<Status>) {
+    loop {
+        if token.is_cancelled() { log::trace!("[api][reverse] exit"); break; }
+
+        let stream = match TcpStream::connect(addr).await {
+            Ok(s) => s,
+            Err(e) => {
+                log::warn!("unable to connect to api coordinator {}: {}", addr, e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            },
+        };
+        log::info!("connected to api coordinator {}", addr);
+
+        if let Err(e) = handle_connection(stream, &l4_tx, &status, true).await {
+            log::warn!("[api] coordinator connection lost: {}", e);
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Serves requests off `stream`. In `persistent` mode (reverse-connect) the
+/// connection is kept open and requests are served in a loop until the peer
+/// closes it or a read/write fails; otherwise (listen mode) exactly one
+/// request is served and the connection is closed, matching how ordinary
+/// HTTP clients dial in.
+async fn handle_connection(stream: TcpStream, l4_tx: &mpsc::Sender<WolMessage>, status: &Arc<Status>, persistent: bool) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await? == 0 {
+            // peer closed the connection
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length: usize = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let line = line.trim_end();
+            if line.is_empty() { break; }
+            if let Some(len) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                content_length = len.trim().parse().unwrap_or(0);
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        if content_length > 0 {
+            reader.read_exact(&mut body).await?;
+        }
+
+        let (status_line, body_out) = match (method.as_str(), path.as_str()) {
+            ("POST", "/wake") => handle_wake(&body, l4_tx).await,
+            ("GET", "/status") => handle_status(status).await,
+            _ => ("HTTP/1.1 404 Not Found", "{\"error\":\"not found\"}".to_string()),
+        };
+
+        let connection = if persistent { "keep-alive" } else { "close" };
+        let response = format!(
+            "{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n{}",
+            status_line, body_out.len(), connection, body_out
+        );
+        writer.write_all(response.as_bytes()).await?;
+        writer.flush().await?;
+
+        if !persistent {
+            return Ok(());
+        }
+    }
+}
+
+async fn handle_wake(body: &[u8], l4_tx: &mpsc::Sender<WolMessage>) -> (&'static str, String) {
+    let req: WakeRequest = match serde_json::from_slice(body) {
+        Ok(r) => r,
+        Err(e) => return ("HTTP/1.1 400 Bad Request", format!("{{\"error\":\"{}\"}}", e)),
+    };
+
+    let target: MacAddr = match req.mac.parse() {
+        Ok(m) => m,
+        Err(_) => return ("HTTP/1.1 400 Bad Request", "{\"error\":\"invalid mac\"}".to_string()),
+    };
+
+    let payload = common::build_wol_payload(target);
+    let msg = WolMessage {
+        src: SocketAddr::from(([0, 0, 0, 0], 0)),
+        target,
+        msg: Box::from(&payload[..]),
+        via_tunnel: false,
+    };
+
+    match l4_tx.send(msg).await {
+        Ok(()) => ("HTTP/1.1 200 OK", "{\"status\":\"queued\"}".to_string()),
+        Err(_) => ("HTTP/1.1 503 Service Unavailable", "{\"error\":\"relay channel closed\"}".to_string()),
+    }
+}
+
+async fn handle_status(status: &Arc<Status>) -> (&'static str, String) {
+    let rate_limited = status.rate_limiter.lock().await.limited_macs().map(|m| m.to_string()).collect();
+
+    match serde_json::to_string(&StatusResponse { rate_limited }) {
+        Ok(json) => ("HTTP/1.1 200 OK", json),
+        Err(_) => ("HTTP/1.1 500 Internal Server Error", "{}".to_string()),
+    }
+}
@@ -1,8 +1,153 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
 use pnet::util::MacAddr;
 
 
 pub const BROADCAST_MAC: [u8; 6] = [ 0xff, 0xff, 0xff, 0xff, 0xff, 0xff ];
 
+/// Default time-to-live for entries in a [`LearningTable`] before `housekeep` evicts them.
+pub const DEFAULT_LEARN_TTL: Duration = Duration::from_secs(300);
+
+/// Default token-bucket capacity (burst) per target MAC.
+pub const DEFAULT_RATE_LIMIT_BURST: u32 = 5;
+/// Default per-MAC refill rate, in tokens (i.e. allowed relayed packets) per second.
+pub const DEFAULT_RATE_LIMIT_REFILL: f64 = 0.2;
+/// Default cap on packets relayed per second across all targets, protecting the broadcast domain.
+pub const DEFAULT_GLOBAL_PPS: f64 = 20.0;
+/// How long an idle per-MAC bucket is kept before `RateLimiter::housekeep` evicts it.
+const RATE_LIMIT_IDLE_TTL: Duration = Duration::from_secs(600);
+
+/// Remembers which `destination` a MAC address was last observed on, so relayed
+/// packets for a known target can be sent there directly instead of flooding
+/// every destination. Modeled after vpncloud's `Table::learn`/`lookup`/`housekeep`.
+pub struct LearningTable<D: Clone> {
+    entries: HashMap<MacAddr, (D, Instant)>,
+    ttl: Duration,
+}
+
+impl<D: Clone> LearningTable<D> {
+    pub fn new(ttl: Duration) -> Self {
+        LearningTable { entries: HashMap::new(), ttl }
+    }
+
+    pub fn learn(&mut self, mac: MacAddr, destination: D) {
+        self.entries.insert(mac, (destination, Instant::now()));
+    }
+
+    pub fn lookup(&self, mac: &MacAddr) -> Option<D> {
+        self.entries.get(mac)
+            .filter(|(_, seen)| seen.elapsed() < self.ttl)
+            .map(|(destination, _)| destination.clone())
+    }
+
+    pub fn housekeep(&mut self) {
+        self.entries.retain(|_, (_, seen)| seen.elapsed() < self.ttl);
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(initial: f64) -> Self {
+        TokenBucket { tokens: initial, last_refill: Instant::now() }
+    }
+
+    /// Refills based on elapsed time since the last call, then takes one
+    /// token if available.
+    fn try_take(&mut self, capacity: f64, refill_per_sec: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * refill_per_sec).min(capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_depleted(&self) -> bool {
+        self.tokens < 1.0
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_refill.elapsed()
+    }
+}
+
+/// Per-MAC token-bucket rate limiter with a global packets-per-second cap,
+/// protecting the broadcast domain against magic-packet floods while still
+/// allowing legitimate retries. Replaces a fixed-cooldown `HashMap<MacAddr, Instant>`
+/// check, which only evicted an entry once another packet for that MAC
+/// happened to arrive after expiry and so grew unboundedly under spoofed or
+/// widely varied target MACs.
+pub struct RateLimiter {
+    per_mac_capacity: f64,
+    per_mac_refill: f64,
+    buckets: HashMap<MacAddr, TokenBucket>,
+    global: TokenBucket,
+    global_capacity: f64,
+    global_refill: f64,
+}
+
+impl RateLimiter {
+    pub fn new(per_mac_burst: u32, per_mac_refill_per_sec: f64, global_pps: f64) -> Self {
+        RateLimiter {
+            per_mac_capacity: per_mac_burst as f64,
+            per_mac_refill: per_mac_refill_per_sec,
+            buckets: HashMap::new(),
+            global: TokenBucket::new(global_pps),
+            global_capacity: global_pps,
+            global_refill: global_pps,
+        }
+    }
+
+    /// Returns `true` if a packet for `mac` may be relayed now. Checks (and
+    /// consumes from) the per-MAC bucket first, so a single flooded MAC is
+    /// capped locally without spending down the global budget other targets
+    /// rely on.
+    pub fn allow(&mut self, mac: MacAddr) -> bool {
+        let bucket = self.buckets.entry(mac)
+            .or_insert_with(|| TokenBucket::new(self.per_mac_capacity));
+        if !bucket.try_take(self.per_mac_capacity, self.per_mac_refill) {
+            return false;
+        }
+
+        self.global.try_take(self.global_capacity, self.global_refill)
+    }
+
+    /// Evicts per-MAC buckets that have been idle long enough to be safely
+    /// forgotten, keeping memory bounded under spoofed/varied target MACs.
+    pub fn housekeep(&mut self) {
+        self.buckets.retain(|_, b| b.idle_for() < RATE_LIMIT_IDLE_TTL);
+    }
+
+    /// MACs currently out of tokens, e.g. for reporting via `GET /status`.
+    pub fn limited_macs(&self) -> impl Iterator<Item = MacAddr> + '_ {
+        self.buckets.iter().filter(|(_, b)| b.is_depleted()).map(|(mac, _)| *mac)
+    }
+}
+
+/// Builds the 102-byte magic packet for `target`: 6 bytes of `0xff` followed by the
+/// target MAC repeated 16 times. The inverse of [`check_wol_payload`]/[`wol_payload_get_target_mac`].
+pub fn build_wol_payload(target: MacAddr) -> [u8; 102] {
+    let mut payload = [0u8; 102];
+    payload[0..6].copy_from_slice(&BROADCAST_MAC);
+
+    let mac_bytes = [target.0, target.1, target.2, target.3, target.4, target.5];
+    for block in payload[6..].chunks_mut(6) {
+        block.copy_from_slice(&mac_bytes);
+    }
+
+    payload
+}
+
 
 pub fn check_wol_payload(payload: &[u8]) -> bool {
     if payload.len() < 102 { return false; }
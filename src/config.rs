@@ -1,14 +1,21 @@
 use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use anyhow::{anyhow, Context, Result};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use serde::{Deserialize, Deserializer};
+use tokio::sync::mpsc;
 
 use crate::{
     layer2::Layer2Config,
-    layer4::Layer4Config
+    layer4::Layer4Config,
+    tunnel::TunnelConfig
 };
 
-/// Deserializes an absent field as None and an unset field as T::default. 
-/// 
+/// Deserializes an absent field as None and an unset field as T::default.
+///
 /// This avoid having Option<Option<T>> as in serde_with::rust::double_option
 pub fn deserialize_absent_or_null<'de, D, T: Default>(deserializer: D) -> Result<Option<T>, D::Error>
 where
@@ -18,12 +25,56 @@ where
     Ok(Option::deserialize(deserializer)?.or(Some(T::default())))
 }
 
+/// Deserializes a plain `u64` seconds value (the same unit the `--*-mac-learn-ttl`
+/// CLI flags take) into a [`Duration`], rather than serde's default struct-of-secs-and-nanos.
+pub fn deserialize_duration_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+}
+
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     #[serde(default, deserialize_with = "deserialize_absent_or_null")]
     pub layer2: Option<Layer2Config>,
-    
+
     #[serde(default, deserialize_with = "deserialize_absent_or_null")]
     pub layer4: Option<Layer4Config>,
+
+    #[serde(default, deserialize_with = "deserialize_absent_or_null")]
+    pub tunnel: Option<TunnelConfig>,
+}
+
+/// Loads a [`Config`] from a TOML or YAML file, picked by the file's extension.
+pub fn load_config(path: &Path) -> Result<Config> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file '{}'", path.display()))?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents)
+            .with_context(|| format!("failed to parse '{}' as TOML", path.display())),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)
+            .with_context(|| format!("failed to parse '{}' as YAML", path.display())),
+        other => Err(anyhow!("unsupported config file extension {:?} (expected .toml, .yaml or .yml)", other)),
+    }
+}
+
+/// Watches `path` for modifications and pings `tx` once per event, so `main`
+/// can reload the config and reconstruct the affected workers without a
+/// restart (as wireguard-rs does for its interface config). The caller is
+/// expected to debounce, since editors commonly emit several modify events
+/// per save. The returned watcher must be kept alive for as long as watching
+/// should continue; dropping it stops the watch.
+pub fn watch_for_changes(path: PathBuf, tx: mpsc::Sender<()>) -> Result<RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        match res {
+            Ok(event) if event.kind.is_modify() => { let _ = tx.try_send(()); },
+            Ok(_) => (),
+            Err(e) => log::warn!("[config] watch error: {}", e),
+        }
+    })?;
+    watcher.watch(path.as_path(), RecursiveMode::NonRecursive)?;
+    Ok(watcher)
 }
@@ -1,16 +1,15 @@
 use std::thread::JoinHandle;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::io::ErrorKind;
 use std::fmt::Debug;
-use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use pnet::datalink::{
-    self, 
-    Channel, 
-    Config, 
-    DataLinkSender, 
+    self,
+    Channel,
+    Config,
+    DataLinkSender,
     NetworkInterface
 };
 use pnet::packet::{
@@ -18,15 +17,44 @@ use pnet::packet::{
     Packet
 };
 use pnet::util::MacAddr;
+use serde::Deserialize;
 use tokio_util::sync::CancellationToken;
 
 use crate::common;
+use crate::config::deserialize_duration_secs;
+use crate::pcap::{CapturedPacket, PcapSender, LINKTYPE_ETHERNET};
+use crate::tunnel::TunnelSender;
 
 pub const ETHERTYPE_WOL: u16 = 0x0842;
 
-#[derive(Debug, Default)]
+/// How often the relay thread sweeps the MAC learning table for expired entries.
+const HOUSEKEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct Layer2Config {
     pub interfaces: Vec<String>,
+    /// How long a learned (MAC -> interface) mapping is trusted before it's relearned by flooding.
+    #[serde(deserialize_with = "deserialize_duration_secs")]
+    pub learn_ttl: Duration,
+    /// Token-bucket capacity (burst) per target MAC before packets are dropped.
+    pub rate_limit_burst: u32,
+    /// Per-MAC token refill rate, in allowed relayed packets per second.
+    pub rate_limit_refill_per_sec: f64,
+    /// Cap on packets relayed per second across all targets.
+    pub global_pps: f64,
+}
+
+impl Default for Layer2Config {
+    fn default() -> Self {
+        Layer2Config {
+            interfaces: Vec::new(),
+            learn_ttl: common::DEFAULT_LEARN_TTL,
+            rate_limit_burst: common::DEFAULT_RATE_LIMIT_BURST,
+            rate_limit_refill_per_sec: common::DEFAULT_RATE_LIMIT_REFILL,
+            global_pps: common::DEFAULT_GLOBAL_PPS,
+        }
+    }
 }
 
 struct WolMessage<'a> {
@@ -41,7 +69,7 @@ fn l2_wol_check(pkt: &EthernetPacket) -> bool {
         crate::common::check_wol_payload(pkt.payload())
 }
 
-pub fn l2_worker(cfg: Layer2Config, token: CancellationToken) -> Result<Vec<JoinHandle<()>>> {
+pub fn l2_worker(cfg: Layer2Config, token: CancellationToken, pcap_tx: Option<PcapSender>, tunnel_tx: TunnelSender) -> Result<Vec<JoinHandle<()>>> {
     let interfaces: Vec<NetworkInterface> = pnet::datalink::interfaces()
         .into_iter()
         .filter(|iface| cfg.interfaces.contains(&iface.name))
@@ -54,7 +82,9 @@ pub fn l2_worker(cfg: Layer2Config, token: CancellationToken) -> Result<Vec<Join
     let mut handles: Vec<JoinHandle<()>> = Vec::new();
     let mut senders: Vec<(u32, Box<dyn DataLinkSender>)> = Vec::new();
     let (mpsc_tx, mpsc_rx) = mpsc::sync_channel::<WolMessage>(8);
-    
+
+    let mac_table = Arc::new(Mutex::new(common::LearningTable::<u32>::new(cfg.learn_ttl)));
+
     let mut dl_cfg = Config::default();
     dl_cfg.read_timeout = Some(Duration::from_millis(50));
     dl_cfg.write_timeout = Some(Duration::from_millis(500));
@@ -73,8 +103,11 @@ pub fn l2_worker(cfg: Layer2Config, token: CancellationToken) -> Result<Vec<Join
         let mpsc_tx = mpsc_tx.clone();
         let token = token.clone();
         let iface = iface.clone();
+        let mac_table = mac_table.clone();
+        let pcap_tx = pcap_tx.clone();
+        let tunnel_tx = tunnel_tx.clone();
 
-        /* 
+        /*
          * Not using tasks here. Due to the amount of incoming Ethernet frames, this can lead to the issue that
          * the TX tasks consume most of the time and the RX task won't run unless the TX tasks yield control
          * (e.g. when the mpsc's buffer is full and then send blocks).
@@ -95,10 +128,20 @@ pub fn l2_worker(cfg: Layer2Config, token: CancellationToken) -> Result<Vec<Join
                             iface.name, eth_pkt.get_source(), eth_pkt.get_destination(),
                             eth_pkt.get_ethertype());
 
+                        // Learn from every frame, not just WOL ones, so the table is
+                        // populated before the first wake request for a given target.
+                        mac_table.lock().unwrap().learn(eth_pkt.get_source(), iface.index);
+
                         if !l2_wol_check(&eth_pkt) { continue; }
 
                         log::debug!("[listener][{}] received WakeOnLan Ethernet packet", iface.name);
 
+                        if let Some(tx) = &pcap_tx {
+                            tx.try_send(CapturedPacket { linktype: LINKTYPE_ETHERNET, data: eth_pkt.packet().to_vec() }).ok();
+                        }
+
+                        tunnel_tx.try_send(eth_pkt.payload().to_vec().into_boxed_slice()).ok();
+
                         let pkt = EthernetPacket::owned(eth_pkt.packet().to_vec()).unwrap();
                         mpsc_tx.send(WolMessage { 
                             iface: iface.clone(),
@@ -115,32 +158,54 @@ pub fn l2_worker(cfg: Layer2Config, token: CancellationToken) -> Result<Vec<Join
     }
 
     let token = token.clone();
+    let pcap_tx = pcap_tx.clone();
+    let mut rate_limiter = common::RateLimiter::new(cfg.rate_limit_burst, cfg.rate_limit_refill_per_sec, cfg.global_pps);
     let h = std::thread::spawn(move || {
-        let mut cooldown_list: HashMap<MacAddr, Instant> = HashMap::new();
+        let mut last_housekeep = Instant::now();
 
         loop {
             if token.is_cancelled() { log::trace!("[relay] exit"); break; }
 
+            if last_housekeep.elapsed() >= HOUSEKEEP_INTERVAL {
+                mac_table.lock().unwrap().housekeep();
+                rate_limiter.housekeep();
+                last_housekeep = Instant::now();
+            }
+
             let wol_msg = match mpsc_rx.recv_timeout(Duration::from_millis(50)) {
                 Ok(msg) => msg,
                 Err(mpsc::RecvTimeoutError::Timeout) => continue,
                 Err(mpsc::RecvTimeoutError::Disconnected) => break,
             };
-            
-            if let Some(t) = cooldown_list.get(&wol_msg.target) {
-                if t.elapsed() < common::COOLDOWN_DUR {
-                    continue;
-                } else {
-                    cooldown_list.remove(&wol_msg.target);
-                }
+
+            if !rate_limiter.allow(wol_msg.target) {
+                log::debug!("[relay] dropping WakeOnLan packet for {}, rate limited", wol_msg.target);
+                continue;
             }
 
             log::debug!("[relay] relaying WakeOnLan packet from {}", wol_msg.pkt.get_source());
 
-            for (if_idx, sender) in senders.iter_mut() {
-                if *if_idx != wol_msg.iface.index {
-                    sender.send_to(wol_msg.pkt.packet(), None);
-                }
+            let known_if = mac_table.lock().unwrap().lookup(&wol_msg.target);
+            match known_if {
+                Some(if_idx) if if_idx != wol_msg.iface.index => {
+                    log::trace!("[relay] target {} known on interface index {}, relaying directly", wol_msg.target, if_idx);
+                    if let Some((_, sender)) = senders.iter_mut().find(|(idx, _)| *idx == if_idx) {
+                        sender.send_to(wol_msg.pkt.packet(), None);
+                        if let Some(tx) = &pcap_tx {
+                            tx.try_send(CapturedPacket { linktype: LINKTYPE_ETHERNET, data: wol_msg.pkt.packet().to_vec() }).ok();
+                        }
+                    }
+                },
+                _ => {
+                    for (if_idx, sender) in senders.iter_mut() {
+                        if *if_idx != wol_msg.iface.index {
+                            sender.send_to(wol_msg.pkt.packet(), None);
+                            if let Some(tx) = &pcap_tx {
+                                tx.try_send(CapturedPacket { linktype: LINKTYPE_ETHERNET, data: wol_msg.pkt.packet().to_vec() }).ok();
+                            }
+                        }
+                    }
+                },
             }
         }
     });
@@ -1,29 +1,52 @@
-use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use std::{fmt::Debug, net::Ipv4Addr};
-use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use anyhow::{anyhow, Result};
 use pnet::ipnetwork::{
     IpNetwork,
     Ipv4Network,
+    Ipv6Network,
     IpNetworkError
 };
 use pnet::util::MacAddr;
+use serde::Deserialize;
 
 use tokio::{
     net::UdpSocket,
-    sync::mpsc,
+    sync::{mpsc, Mutex},
     task::JoinSet,
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::common;
+use crate::pcap::{CapturedPacket, PcapSender, LINKTYPE_RAW};
+use crate::tunnel::TunnelSender;
 
-#[derive(Debug)]
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct Layer4Config {
     pub listen_on: Vec<SocketAddr>,
     pub relay_to: Vec<IpNetwork>,
+    /// Token-bucket capacity (burst) per target MAC before packets are dropped.
+    pub rate_limit_burst: u32,
+    /// Per-MAC token refill rate, in allowed relayed packets per second.
+    pub rate_limit_refill_per_sec: f64,
+    /// Cap on packets relayed per second across all targets.
+    pub global_pps: f64,
+}
+
+impl Default for Layer4Config {
+    fn default() -> Self {
+        Layer4Config {
+            listen_on: Vec::new(),
+            relay_to: Vec::new(),
+            rate_limit_burst: common::DEFAULT_RATE_LIMIT_BURST,
+            rate_limit_refill_per_sec: common::DEFAULT_RATE_LIMIT_REFILL,
+            global_pps: common::DEFAULT_GLOBAL_PPS,
+        }
+    }
 }
 
 const IPV4_PRIVATE_A: Result<Ipv4Network, IpNetworkError> = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 0), 8);
@@ -31,10 +54,33 @@ const IPV4_PRIVATE_B: Result<Ipv4Network, IpNetworkError> = Ipv4Network::new(Ipv
 const IPV4_PRIVATE_C: Result<Ipv4Network, IpNetworkError> = Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 0), 16);
 const IPV4_UNSPEC: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
 
-struct WolMessage {
-    src: SocketAddr,
-    target: MacAddr,
-    msg: Box<[u8]>,
+// Unique local addresses, fc00::/7
+const IPV6_ULA: Result<Ipv6Network, IpNetworkError> = Ipv6Network::new(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7);
+// Link-local addresses, fe80::/10
+const IPV6_LINK_LOCAL: Result<Ipv6Network, IpNetworkError> = Ipv6Network::new(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10);
+// All-nodes multicast group, used in place of broadcast since IPv6 has none
+const IPV6_ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// How often the relay task sweeps the MAC learning table for expired entries.
+const HOUSEKEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) struct WolMessage {
+    pub(crate) src: SocketAddr,
+    pub(crate) target: MacAddr,
+    pub(crate) msg: Box<[u8]>,
+    /// Set when this message was re-injected after arriving over the tunnel,
+    /// so the relay loop doesn't forward it back out the tunnel it just came from.
+    pub(crate) via_tunnel: bool,
+}
+
+/// Relay state shared with the `api` module so `GET /status` can report
+/// currently rate-limited targets without duplicating it.
+///
+/// Unlike `layer2`, L4 has no way to observe which network a target actually
+/// sits on (it only ever sees the requester's source address), so it floods
+/// every configured network on every wake instead of learning a mapping.
+pub(crate) struct Status {
+    pub(crate) rate_limiter: Mutex<common::RateLimiter>,
 }
 
 fn is_private_network(net: &IpNetwork) -> bool {
@@ -44,20 +90,27 @@ fn is_private_network(net: &IpNetwork) -> bool {
                 v4net.is_subnet_of(IPV4_PRIVATE_B.unwrap()) ||
                 v4net.is_subnet_of(IPV4_PRIVATE_C.unwrap())
         },
-        IpNetwork::V6(_) => unreachable!(),
+        IpNetwork::V6(v6net) => {
+            v6net.is_subnet_of(IPV6_ULA.unwrap()) ||
+                v6net.is_subnet_of(IPV6_LINK_LOCAL.unwrap())
+        },
     }
 }
 
-fn sanitize_destination_networks(mut relay_to: Vec<IpNetwork>) -> Result<Vec<IpNetwork>> {
+/// Destination network paired with the index of the interface it was learned on,
+/// needed to pick the outgoing multicast interface for IPv6 destinations.
+fn sanitize_destination_networks(mut relay_to: Vec<IpNetwork>) -> Result<Vec<(IpNetwork, u32)>> {
     let networks_avail = pnet::datalink::interfaces()
         .into_iter()
-        .flat_map(|e| e.ips)
-        .filter_map(|net| {
-            (net.is_ipv4() && is_private_network(&net))
-                .then_some(IpNetwork::new(net.network(), net.prefix()).unwrap())
+        .flat_map(|iface| {
+            let if_idx = iface.index;
+            iface.ips.into_iter().filter_map(move |net| {
+                is_private_network(&net)
+                    .then_some((IpNetwork::new(net.network(), net.prefix()).unwrap(), if_idx))
+            })
         })
-        .collect::<Vec<IpNetwork>>();
-    
+        .collect::<Vec<(IpNetwork, u32)>>();
+
     if networks_avail.len() == 0 { return Err(anyhow!("no available networks!")) }
     if relay_to.len() == 0 { return Err(anyhow!("no relay networks specified!")) }
 
@@ -68,27 +121,112 @@ fn sanitize_destination_networks(mut relay_to: Vec<IpNetwork>) -> Result<Vec<IpN
     relay_to.sort();
     relay_to.dedup();
 
-    let mut networks: Vec<IpNetwork>;
+    let mut networks: Vec<(IpNetwork, u32)>;
     if relay_to[0].ip().is_unspecified() {
         networks = networks_avail;
     } else {
         networks = Vec::new();
         for net in relay_to {
-            if !networks_avail.contains(&net) {
-                log::warn!("network {} is not available!", net);
+            match networks_avail.iter().find(|(avail, _)| *avail == net) {
+                Some((avail, if_idx)) => networks.push((*avail, *if_idx)),
+                None => {
+                    log::warn!("network {} is not available!", net);
+                    networks.push((net, 0));
+                },
             }
-
-            networks.push(net);
         }
     }
 
-    // There can be duplicates in some cases. 
+    // There can be duplicates in some cases.
     networks.sort();
     networks.dedup();
     Ok(networks)
 }
 
-pub fn l4_worker(cfg: Layer4Config, token: CancellationToken) -> Result<JoinSet<()>> {
+/// Builds a minimal IPv4/UDP or IPv6/UDP datagram around a UDP payload so it
+/// can be captured with `LINKTYPE_RAW`; only the payload is available at this
+/// layer since no real IP header was ever assembled. The IPv4 header checksum
+/// is computed; UDP checksums are left unset (valid for IPv4 UDP, debug-only
+/// shortcut for IPv6 UDP).
+fn synthesize_ip_udp(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+
+    // A packet received over one address family can be relayed to a network
+    // of the other (e.g. an IPv4 listener relaying to an IPv6 network), so
+    // express the source in the destination's family.
+    let src = match (src, dst) {
+        (SocketAddr::V4(s), SocketAddr::V6(_)) => SocketAddr::new(IpAddr::V6(s.ip().to_ipv6_mapped()), s.port()),
+        (SocketAddr::V6(s), SocketAddr::V4(_)) => SocketAddr::new(IpAddr::V4(s.ip().to_ipv4_mapped().unwrap_or(Ipv4Addr::UNSPECIFIED)), s.port()),
+        _ => src,
+    };
+
+    match (src, dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            let total_len = 20 + udp_len;
+            let mut pkt = Vec::with_capacity(total_len);
+
+            pkt.push(0x45); // version 4, IHL 5
+            pkt.push(0); // DSCP/ECN
+            pkt.extend_from_slice(&(total_len as u16).to_be_bytes());
+            pkt.extend_from_slice(&0u16.to_be_bytes()); // identification
+            pkt.extend_from_slice(&0u16.to_be_bytes()); // flags/fragment offset
+            pkt.push(64); // TTL
+            pkt.push(17); // protocol: UDP
+            pkt.extend_from_slice(&0u16.to_be_bytes()); // checksum placeholder
+            pkt.extend_from_slice(&src.ip().octets());
+            pkt.extend_from_slice(&dst.ip().octets());
+
+            let checksum = ipv4_checksum(&pkt);
+            pkt[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+            pkt.extend_from_slice(&src.port().to_be_bytes());
+            pkt.extend_from_slice(&dst.port().to_be_bytes());
+            pkt.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            pkt.extend_from_slice(&0u16.to_be_bytes()); // UDP checksum optional over IPv4
+            pkt.extend_from_slice(payload);
+
+            pkt
+        },
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            let mut pkt = Vec::with_capacity(40 + udp_len);
+
+            pkt.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, traffic class, flow label
+            pkt.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            pkt.push(17); // next header: UDP
+            pkt.push(64); // hop limit
+            pkt.extend_from_slice(&src.ip().octets());
+            pkt.extend_from_slice(&dst.ip().octets());
+
+            pkt.extend_from_slice(&src.port().to_be_bytes());
+            pkt.extend_from_slice(&dst.port().to_be_bytes());
+            pkt.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            pkt.extend_from_slice(&0u16.to_be_bytes()); // UDP checksum skipped, debug-only capture
+            pkt.extend_from_slice(payload);
+
+            pkt
+        },
+        _ => Vec::new(), // families were aligned above, not reached
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header.chunks(2)
+        .map(|c| if c.len() == 2 { u16::from_be_bytes([c[0], c[1]]) as u32 } else { (c[0] as u32) << 8 })
+        .sum();
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// Starts the layer 4 worker and returns its task set, a sender handle for
+/// the relay channel (so other subsystems like `tunnel`/`api` can inject
+/// `WolMessage`s and have them go through the same rate-limited/broadcast
+/// logic as packets received on the configured sockets), and the shared
+/// `Status` backing `GET /status`.
+pub fn l4_worker(cfg: Layer4Config, token: CancellationToken, pcap_tx: Option<PcapSender>, tunnel_tx: TunnelSender) -> Result<(JoinSet<()>, mpsc::Sender<WolMessage>, Arc<Status>)> {
     let (mpsc_tx, mut mpsc_rx) = mpsc::channel::<WolMessage>(8);
     let mut tasks: JoinSet<()> = JoinSet::new();
 
@@ -96,11 +234,15 @@ pub fn l4_worker(cfg: Layer4Config, token: CancellationToken) -> Result<JoinSet<
         Some(addr) => vec![addr.clone()],
         None => cfg.listen_on,
     };
-    let networks = sanitize_destination_networks(cfg.relay_to)?;
+    let networks = Arc::new(sanitize_destination_networks(cfg.relay_to)?);
+    let status = Arc::new(Status {
+        rate_limiter: Mutex::new(common::RateLimiter::new(cfg.rate_limit_burst, cfg.rate_limit_refill_per_sec, cfg.global_pps)),
+    });
 
     for addr in listen_on {
         let mpsc_tx = mpsc_tx.clone();
         let token = token.clone();
+        let pcap_tx = pcap_tx.clone();
 
         tasks.spawn(async move {
             let sock = match UdpSocket::bind(addr).await {
@@ -127,10 +269,18 @@ pub fn l4_worker(cfg: Layer4Config, token: CancellationToken) -> Result<JoinSet<
                 }
                 log::debug!("received WOL message from {}", from);
 
+                if let Some(tx) = &pcap_tx {
+                    let data = synthesize_ip_udp(from, addr, &buf[..len]);
+                    tx.try_send(CapturedPacket { linktype: LINKTYPE_RAW, data }).ok();
+                }
+
+                let target = common::wol_payload_get_target_mac(&buf[..len]);
+
                 mpsc_tx.send(WolMessage {
                     src: from,
-                    target: common::wol_payload_get_target_mac(&buf[..len]),
-                    msg: Box::from(&buf[..len])
+                    target,
+                    msg: Box::from(&buf[..len]),
+                    via_tunnel: false,
                 }).await.ok();
             }
         });
@@ -141,24 +291,44 @@ pub fn l4_worker(cfg: Layer4Config, token: CancellationToken) -> Result<JoinSet<
         log::debug!("relay to network: {}", net);
     }
 
+    let relay_networks = networks.clone();
+    let relay_status = status.clone();
+    let relay_pcap_tx = pcap_tx.clone();
+    let relay_tunnel_tx = tunnel_tx.clone();
     tasks.spawn(async move {
+        let networks = relay_networks;
+        let status = relay_status;
+        let pcap_tx = relay_pcap_tx;
+        let tunnel_tx = relay_tunnel_tx;
         const SOCKADDR_UNSPEC: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-        let sock = match UdpSocket::bind(SOCKADDR_UNSPEC).await {
+        const SOCKADDR_UNSPEC_V6: SocketAddr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0));
+
+        let sock_v4 = match UdpSocket::bind(SOCKADDR_UNSPEC).await {
             Ok(s) => s,
             Err(e) => { log::error!("unable to bind to socket: {}", e); return; }
         };
-        match sock.set_broadcast(true) {
+        match sock_v4.set_broadcast(true) {
             Ok(_) => (),
             Err(e) => { log::error!("unable to set SO_BROADCAST on socket: {}", e); return; }
         };
 
-        let mut cooldown_list: HashMap<MacAddr, Instant> = HashMap::new();
-        
+        let sock_v6 = match UdpSocket::bind(SOCKADDR_UNSPEC_V6).await {
+            Ok(s) => s,
+            Err(e) => { log::error!("unable to bind to v6 socket: {}", e); return; }
+        };
+
+        let mut last_housekeep = Instant::now();
+
         loop {
             if token.is_cancelled() { log::trace!("[relay] exit"); break; }
 
+            if last_housekeep.elapsed() >= HOUSEKEEP_INTERVAL {
+                status.rate_limiter.lock().await.housekeep();
+                last_housekeep = Instant::now();
+            }
+
             let msg = match tokio::time::timeout(
-                Duration::from_millis(50), 
+                Duration::from_millis(50),
                 mpsc_rx.recv()
             ).await {
                 Ok(Some(m)) => m,
@@ -166,27 +336,54 @@ pub fn l4_worker(cfg: Layer4Config, token: CancellationToken) -> Result<JoinSet<
                 Err(_) => continue,
             };
 
-            if let Some(t) = cooldown_list.get(&msg.target) {
-                if t.elapsed() < common::COOLDOWN_DUR {
-                    continue;
-                } else {
-                    cooldown_list.remove(&msg.target);
-                }
+            if !status.rate_limiter.lock().await.allow(msg.target) {
+                log::debug!("dropping message for {}, rate limited", msg.target);
+                continue;
             }
 
             log::debug!("relay message from {} to networks", msg.src);
 
-            for net in networks.iter() {
-                log::trace!("relaying message from {} to {}", msg.src, net);
-                sock.send_to(
-                    &msg.msg, 
-                    SocketAddr::new(net.broadcast(), 9)
-                ).await.ok();
+            // Forward to tunnel peers too, unless this message just arrived
+            // over the tunnel in the first place -- otherwise a wake bounced
+            // between two sites would never stop circulating.
+            if !msg.via_tunnel {
+                tunnel_tx.try_send(msg.msg.clone()).ok();
             }
 
-            cooldown_list.insert(msg.target, Instant::now());
+            // L4 never observes which network the target itself is on (only
+            // the requester's source address), so unlike layer2 it always
+            // floods every configured network rather than learning one.
+            for (net, if_idx) in networks.iter() {
+                log::trace!("relaying message from {} to {}", msg.src, net);
+
+                match net {
+                    IpNetwork::V4(_) => {
+                        let dest = SocketAddr::new(net.broadcast(), 9);
+                        sock_v4.send_to(&msg.msg, dest).await.ok();
+
+                        if let Some(tx) = &pcap_tx {
+                            let data = synthesize_ip_udp(msg.src, dest, &msg.msg);
+                            tx.try_send(CapturedPacket { linktype: LINKTYPE_RAW, data }).ok();
+                        }
+                    },
+                    IpNetwork::V6(_) => {
+                        if let Err(e) = socket2::SockRef::from(&sock_v6).set_multicast_if_v6(*if_idx) {
+                            log::warn!("unable to set multicast interface {} on v6 socket: {}", if_idx, e);
+                            continue;
+                        }
+
+                        let dest = SocketAddr::V6(SocketAddrV6::new(IPV6_ALL_NODES, 9, 0, *if_idx));
+                        sock_v6.send_to(&msg.msg, dest).await.ok();
+
+                        if let Some(tx) = &pcap_tx {
+                            let data = synthesize_ip_udp(msg.src, dest, &msg.msg);
+                            tx.try_send(CapturedPacket { linktype: LINKTYPE_RAW, data }).ok();
+                        }
+                    },
+                }
+            }
         }
     });
 
-    Ok(tasks)
+    Ok((tasks, mpsc_tx, status))
 }
@@ -1,39 +1,429 @@
-use std::{net::SocketAddr, thread};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
+use anyhow::{anyhow, Result};
+use api::ApiConfig;
 use layer2::Layer2Config;
-use layer4::Layer4Config;
+use layer4::{Layer4Config, Status, WolMessage};
 use log::LevelFilter;
 use pnet::ipnetwork::IpNetwork;
 use simple_logger::SimpleLogger;
+use tunnel::TunnelConfig;
 
+use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 
 use clap::Parser;
 
+mod api;
 mod common;
+mod config;
 mod layer2;
 mod layer4;
+mod pcap;
+mod tunnel;
 
 #[derive(Parser)]
 struct Cli {
     #[arg(short, long, default_value_t = LevelFilter::Info)]
     log: LevelFilter,
 
+    /// Load layer2/layer4/tunnel settings from a TOML or YAML file, merged with
+    /// the flags below. The file is watched for changes and the affected
+    /// workers are torn down and reconstructed on the fly, without a restart.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     #[arg(long)]
     l2: bool,
 
     #[arg(long)]
     l2_if: Vec<String>,
 
+    /// Seconds a learned (MAC -> interface) mapping is trusted before falling back to flooding.
+    #[arg(long)]
+    l2_mac_learn_ttl: Option<u64>,
+
+    /// Token-bucket capacity (burst) per target MAC before layer2 WOL packets are dropped (shared by all --l2-if interfaces).
+    #[arg(long)]
+    l2_rate_limit_burst: Option<u32>,
+
+    /// Per-MAC token refill rate, in allowed relayed packets per second.
+    #[arg(long)]
+    l2_rate_limit_refill: Option<f64>,
+
+    /// Cap on packets relayed per second across all targets and all --l2-if interfaces.
+    #[arg(long)]
+    l2_global_pps: Option<f64>,
+
     #[arg(long)]
     l4: bool,
 
     #[arg(long)]
     l4_listen_on: Vec<SocketAddr>,
-    
+
     #[arg(long)]
     l4_relay_to: Vec<IpNetwork>,
+
+    /// Token-bucket capacity (burst) per target MAC before layer4 WOL packets are dropped (shared by all
+    /// --l4-listen-on sockets and by wakes injected from the tunnel and the api).
+    #[arg(long)]
+    l4_rate_limit_burst: Option<u32>,
+
+    /// Per-MAC token refill rate, in allowed relayed packets per second.
+    #[arg(long)]
+    l4_rate_limit_refill: Option<f64>,
+
+    /// Cap on packets relayed per second across all targets and all layer4 inputs.
+    #[arg(long)]
+    l4_global_pps: Option<f64>,
+
+    #[arg(long)]
+    tunnel: bool,
+
+    #[arg(long)]
+    tunnel_listen_on: Option<SocketAddr>,
+
+    #[arg(long)]
+    tunnel_peer: Vec<SocketAddr>,
+
+    #[arg(long)]
+    tunnel_group_id: Option<u64>,
+
+    /// Pre-shared key for the tunnel AEAD, as 64 hex characters (32 bytes).
+    #[arg(long)]
+    tunnel_psk: Option<String>,
+
+    /// Capture every WOL packet observed and relayed into a pcap file for debugging.
+    #[arg(long, env = "WOL_RELAY_PCAP_FILE")]
+    pcap: Option<String>,
+
+    /// Serve the HTTP control API (POST /wake, GET /status) on this address.
+    #[arg(long)]
+    api_listen: Option<SocketAddr>,
+
+    /// Reverse-connect to a coordinator and serve the HTTP control API over that outbound link.
+    #[arg(long)]
+    api_connect: Option<SocketAddr>,
+}
+
+/// Combines a config file's `[layer2]` section (if any) with the `--l2-*` flags.
+/// List fields are the union of both sources; scalar fields let the CLI flag
+/// win so a one-off override doesn't require editing the file.
+fn merged_l2_cfg(opts: &Cli, file: Option<&Layer2Config>) -> Layer2Config {
+    let mut interfaces = file.map(|c| c.interfaces.clone()).unwrap_or_default();
+    for i in &opts.l2_if {
+        if !interfaces.contains(i) { interfaces.push(i.clone()); }
+    }
+
+    let learn_ttl = opts.l2_mac_learn_ttl.map(Duration::from_secs)
+        .or_else(|| file.map(|c| c.learn_ttl))
+        .unwrap_or(common::DEFAULT_LEARN_TTL);
+
+    let rate_limit_burst = opts.l2_rate_limit_burst
+        .or_else(|| file.map(|c| c.rate_limit_burst))
+        .unwrap_or(common::DEFAULT_RATE_LIMIT_BURST);
+    let rate_limit_refill_per_sec = opts.l2_rate_limit_refill
+        .or_else(|| file.map(|c| c.rate_limit_refill_per_sec))
+        .unwrap_or(common::DEFAULT_RATE_LIMIT_REFILL);
+    let global_pps = opts.l2_global_pps
+        .or_else(|| file.map(|c| c.global_pps))
+        .unwrap_or(common::DEFAULT_GLOBAL_PPS);
+
+    Layer2Config { interfaces, learn_ttl, rate_limit_burst, rate_limit_refill_per_sec, global_pps }
+}
+
+/// Combines a config file's `[layer4]` section (if any) with the `--l4-*` flags,
+/// same merge rule as [`merged_l2_cfg`].
+fn merged_l4_cfg(opts: &Cli, file: Option<&Layer4Config>) -> Layer4Config {
+    let mut listen_on = file.map(|c| c.listen_on.clone()).unwrap_or_default();
+    for a in &opts.l4_listen_on {
+        if !listen_on.contains(a) { listen_on.push(*a); }
+    }
+
+    let mut relay_to = file.map(|c| c.relay_to.clone()).unwrap_or_default();
+    for n in &opts.l4_relay_to {
+        if !relay_to.contains(n) { relay_to.push(*n); }
+    }
+
+    let rate_limit_burst = opts.l4_rate_limit_burst
+        .or_else(|| file.map(|c| c.rate_limit_burst))
+        .unwrap_or(common::DEFAULT_RATE_LIMIT_BURST);
+    let rate_limit_refill_per_sec = opts.l4_rate_limit_refill
+        .or_else(|| file.map(|c| c.rate_limit_refill_per_sec))
+        .unwrap_or(common::DEFAULT_RATE_LIMIT_REFILL);
+    let global_pps = opts.l4_global_pps
+        .or_else(|| file.map(|c| c.global_pps))
+        .unwrap_or(common::DEFAULT_GLOBAL_PPS);
+
+    Layer4Config { listen_on, relay_to, rate_limit_burst, rate_limit_refill_per_sec, global_pps }
+}
+
+/// Combines a config file's `[tunnel]` section (if any) with the `--tunnel-*`
+/// flags. Returns `None` if neither enables the tunnel.
+fn merged_tunnel_cfg(opts: &Cli, file: Option<&TunnelConfig>) -> Result<Option<TunnelConfig>> {
+    if !opts.tunnel && file.is_none() {
+        return Ok(None);
+    }
+
+    let listen_on = opts.tunnel_listen_on
+        .or_else(|| file.map(|c| c.listen_on))
+        .ok_or_else(|| anyhow!("--tunnel-listen-on or a config file [tunnel] section is required with --tunnel"))?;
+
+    let mut peers = file.map(|c| c.peers.clone()).unwrap_or_default();
+    for p in &opts.tunnel_peer {
+        if !peers.contains(p) { peers.push(*p); }
+    }
+
+    let group_id = opts.tunnel_group_id.or_else(|| file.map(|c| c.group_id)).unwrap_or(0);
+
+    let psk = match &opts.tunnel_psk {
+        Some(hex) => tunnel::parse_psk_hex(hex)?,
+        None => file.map(|c| c.psk)
+            .ok_or_else(|| anyhow!("--tunnel-psk or a config file [tunnel] section is required with --tunnel"))?,
+    };
+
+    Ok(Some(TunnelConfig { listen_on, peers, group_id, psk }))
+}
+
+/// The subset of workers that get torn down and reconstructed on a config
+/// reload: l2, l4, tunnel and api. Each holds its own `CancellationToken` (a
+/// child of the process-wide one) so a reload can cancel and rejoin just the
+/// affected worker's handles without disturbing the others. The last merged
+/// config for l2/l4/tunnel is kept alongside so [`reload_workers`] can tell
+/// which sections actually changed and leave the rest running.
+///
+/// tunnel and api both hold a sender into the l4 relay channel, so whenever
+/// l4 is rebuilt (its own section changed) they're rebuilt alongside it,
+/// bound to the new sender; a tunnel-only or api-only change doesn't touch
+/// l2/l4. The tunnel egress channel itself is independent of all of this
+/// (see [`tunnel::TunnelSender`]), so rebuilding tunnel never requires
+/// rebuilding l2/l4 just to hand them a new sender.
+#[derive(Default)]
+struct Workers {
+    l2_handles: Vec<thread::JoinHandle<()>>,
+    l2_token: CancellationToken,
+    l2_cfg: Option<Layer2Config>,
+
+    l4_handles: Option<JoinSet<()>>,
+    l4_token: CancellationToken,
+    l4_tx: Option<mpsc::Sender<WolMessage>>,
+    l4_status: Option<Arc<Status>>,
+    l4_cfg: Option<Layer4Config>,
+
+    tunnel_handles: Option<JoinSet<()>>,
+    tunnel_token: CancellationToken,
+    tunnel_cfg: Option<TunnelConfig>,
+
+    api_handles: Option<JoinSet<()>>,
+    api_token: CancellationToken,
+}
+
+impl Workers {
+    async fn shut_down(self) {
+        self.l2_token.cancel();
+        self.l4_token.cancel();
+        self.tunnel_token.cancel();
+        self.api_token.cancel();
+
+        self.l2_handles.into_iter().for_each(|h| { let _ = h.join(); });
+        if let Some(tasks) = self.l4_handles { tasks.join_all().await; }
+        if let Some(tasks) = self.tunnel_handles { tasks.join_all().await; }
+        if let Some(tasks) = self.api_handles { tasks.join_all().await; }
+    }
+}
+
+fn spawn_l2(
+    opts: &Cli,
+    file_l2: Option<&Layer2Config>,
+    parent_token: &CancellationToken,
+    pcap_tx: Option<pcap::PcapSender>,
+    tunnel_tx: tunnel::TunnelSender,
+) -> (Vec<thread::JoinHandle<()>>, CancellationToken, Option<Layer2Config>) {
+    let token = parent_token.child_token();
+
+    if !opts.l2 && file_l2.is_none() {
+        return (Vec::new(), token, None);
+    }
+
+    let cfg = merged_l2_cfg(opts, file_l2);
+    let handles = match layer2::l2_worker(cfg.clone(), token.clone(), pcap_tx, tunnel_tx) {
+        Ok(handles) => handles,
+        Err(e) => { log::error!("failed to start layer2 worker: {}", e); Vec::new() },
+    };
+
+    (handles, token, Some(cfg))
+}
+
+fn spawn_l4(
+    opts: &Cli,
+    file_l4: Option<&Layer4Config>,
+    parent_token: &CancellationToken,
+    pcap_tx: Option<pcap::PcapSender>,
+    tunnel_tx: tunnel::TunnelSender,
+) -> (Option<JoinSet<()>>, CancellationToken, Option<mpsc::Sender<WolMessage>>, Option<Arc<Status>>, Option<Layer4Config>) {
+    let token = parent_token.child_token();
+
+    if !opts.l4 && file_l4.is_none() {
+        return (None, token, None, None, None);
+    }
+
+    let cfg = merged_l4_cfg(opts, file_l4);
+    match layer4::l4_worker(cfg.clone(), token.clone(), pcap_tx, tunnel_tx) {
+        Ok((tasks, tx, status)) => (Some(tasks), token, Some(tx), Some(status), Some(cfg)),
+        Err(e) => { log::error!("failed to start layer4 worker: {}", e); (None, token, None, None, Some(cfg)) },
+    }
+}
+
+fn spawn_tunnel(
+    opts: &Cli,
+    file_tunnel: Option<&TunnelConfig>,
+    parent_token: &CancellationToken,
+    l4_tx: Option<mpsc::Sender<WolMessage>>,
+    tunnel_rx: tunnel::TunnelReceiver,
+) -> (Option<JoinSet<()>>, CancellationToken, Option<TunnelConfig>) {
+    let token = parent_token.child_token();
+
+    match (merged_tunnel_cfg(opts, file_tunnel), l4_tx) {
+        (Ok(Some(cfg)), Some(l4_tx)) => match tunnel::tunnel_worker(cfg.clone(), token.clone(), l4_tx, tunnel_rx) {
+            Ok(tasks) => (Some(tasks), token, Some(cfg)),
+            Err(e) => { log::error!("failed to start tunnel worker: {}", e); (None, token, Some(cfg)) },
+        },
+        (Ok(Some(_)), None) => { log::error!("--tunnel requires --l4 to be enabled"); (None, token, None) },
+        (Ok(None), _) => (None, token, None),
+        (Err(e), _) => { log::error!("invalid tunnel configuration: {}", e); (None, token, None) },
+    }
+}
+
+fn spawn_api(
+    opts: &Cli,
+    parent_token: &CancellationToken,
+    l4_tx: Option<mpsc::Sender<WolMessage>>,
+    l4_status: Option<Arc<Status>>,
+) -> (Option<JoinSet<()>>, CancellationToken) {
+    let token = parent_token.child_token();
+
+    if opts.api_listen.is_none() && opts.api_connect.is_none() {
+        return (None, token);
+    }
+
+    match (l4_tx, l4_status) {
+        (Some(l4_tx), Some(status)) => {
+            let api_cfg = ApiConfig { listen_on: opts.api_listen, connect_to: opts.api_connect };
+            match api::api_worker(api_cfg, token.clone(), l4_tx, status) {
+                Ok(tasks) => (Some(tasks), token),
+                Err(e) => { log::error!("failed to start api worker: {}", e); (None, token) },
+            }
+        },
+        _ => { log::error!("--api-listen/--api-connect require --l4 to be enabled"); (None, token) },
+    }
+}
+
+/// Builds every worker from scratch, used once at startup.
+fn spawn_workers(
+    opts: &Cli,
+    file_cfg: Option<&config::Config>,
+    parent_token: &CancellationToken,
+    pcap_tx: Option<pcap::PcapSender>,
+    tunnel_tx: tunnel::TunnelSender,
+    tunnel_rx: tunnel::TunnelReceiver,
+) -> Workers {
+    let file_l2 = file_cfg.and_then(|c| c.layer2.as_ref());
+    let file_l4 = file_cfg.and_then(|c| c.layer4.as_ref());
+    let file_tunnel = file_cfg.and_then(|c| c.tunnel.as_ref());
+
+    let (l2_handles, l2_token, l2_cfg) = spawn_l2(opts, file_l2, parent_token, pcap_tx.clone(), tunnel_tx.clone());
+    let (l4_handles, l4_token, l4_tx, l4_status, l4_cfg) = spawn_l4(opts, file_l4, parent_token, pcap_tx, tunnel_tx);
+    let (tunnel_handles, tunnel_token, tunnel_cfg) = spawn_tunnel(opts, file_tunnel, parent_token, l4_tx.clone(), tunnel_rx);
+    let (api_handles, api_token) = spawn_api(opts, parent_token, l4_tx.clone(), l4_status.clone());
+
+    Workers {
+        l2_handles, l2_token, l2_cfg,
+        l4_handles, l4_token, l4_tx, l4_status, l4_cfg,
+        tunnel_handles, tunnel_token, tunnel_cfg,
+        api_handles, api_token,
+    }
+}
+
+/// Reconstructs only the workers whose merged config actually changed since
+/// `old` was built, rather than tearing everything down on every edit: most
+/// config-file saves touch a single `[section]`, and sections untouched by
+/// the edit keep their sockets open. l4 is the one exception that cascades --
+/// tunnel and api both hold a sender bound to l4's relay channel, so a new
+/// l4 (even if tunnel/api's own settings are unchanged) forces them to be
+/// rebuilt too, bound to the new sender.
+async fn reload_workers(
+    opts: &Cli,
+    file_cfg: Option<&config::Config>,
+    parent_token: &CancellationToken,
+    pcap_tx: Option<pcap::PcapSender>,
+    tunnel_tx: tunnel::TunnelSender,
+    tunnel_rx: tunnel::TunnelReceiver,
+    old: Workers,
+) -> Workers {
+    let file_l2 = file_cfg.and_then(|c| c.layer2.as_ref());
+    let file_l4 = file_cfg.and_then(|c| c.layer4.as_ref());
+    let file_tunnel = file_cfg.and_then(|c| c.tunnel.as_ref());
+
+    let new_l2_cfg = (opts.l2 || file_l2.is_some()).then(|| merged_l2_cfg(opts, file_l2));
+    let new_l4_cfg = (opts.l4 || file_l4.is_some()).then(|| merged_l4_cfg(opts, file_l4));
+    let l4_changed = new_l4_cfg != old.l4_cfg;
+
+    let (l2_handles, l2_token, l2_cfg) = if new_l2_cfg != old.l2_cfg {
+        log::info!("layer2 configuration changed, reconstructing its worker");
+        old.l2_token.cancel();
+        old.l2_handles.into_iter().for_each(|h| { let _ = h.join(); });
+        spawn_l2(opts, file_l2, parent_token, pcap_tx.clone(), tunnel_tx.clone())
+    } else {
+        (old.l2_handles, old.l2_token, old.l2_cfg)
+    };
+
+    let (l4_handles, l4_token, l4_tx, l4_status, l4_cfg) = if l4_changed {
+        log::info!("layer4 configuration changed, reconstructing its worker");
+        old.l4_token.cancel();
+        if let Some(tasks) = old.l4_handles { tasks.join_all().await; }
+        spawn_l4(opts, file_l4, parent_token, pcap_tx, tunnel_tx)
+    } else {
+        (old.l4_handles, old.l4_token, old.l4_tx, old.l4_status, old.l4_cfg)
+    };
+
+    let new_tunnel_cfg_result = merged_tunnel_cfg(opts, file_tunnel);
+    let tunnel_changed = l4_changed || !matches!(&new_tunnel_cfg_result, Ok(cfg) if *cfg == old.tunnel_cfg);
+    let (tunnel_handles, tunnel_token, tunnel_cfg) = if tunnel_changed {
+        log::info!("tunnel configuration changed, reconstructing its worker");
+        old.tunnel_token.cancel();
+        if let Some(tasks) = old.tunnel_handles { tasks.join_all().await; }
+        spawn_tunnel(opts, file_tunnel, parent_token, l4_tx.clone(), tunnel_rx)
+    } else {
+        (old.tunnel_handles, old.tunnel_token, old.tunnel_cfg)
+    };
+
+    let (api_handles, api_token) = if l4_changed {
+        log::info!("api reconstructing to bind to the new layer4 relay channel");
+        old.api_token.cancel();
+        if let Some(tasks) = old.api_handles { tasks.join_all().await; }
+        spawn_api(opts, parent_token, l4_tx.clone(), l4_status.clone())
+    } else {
+        (old.api_handles, old.api_token)
+    };
+
+    Workers {
+        l2_handles, l2_token, l2_cfg,
+        l4_handles, l4_token, l4_tx, l4_status, l4_cfg,
+        tunnel_handles, tunnel_token, tunnel_cfg,
+        api_handles, api_token,
+    }
+}
+
+fn load_config_file(path: &PathBuf) -> Option<config::Config> {
+    match config::load_config(path) {
+        Ok(c) => Some(c),
+        Err(e) => { log::error!("failed to load config file '{}': {}", path.display(), e); None },
+    }
 }
 
 #[tokio::main]
@@ -54,24 +444,52 @@ async fn main() {
 		sigint_token.cancel();
 	}).expect("Failed to install SIGINT handler");
 
-    let mut l2_handles: Vec<thread::JoinHandle<()>> = Vec::new();
-    if opts.l2 {
-        let l2_cfg = Layer2Config { interfaces: opts.l2_if };
-        l2_handles.extend(layer2::l2_worker(l2_cfg, cancel_token.clone()));
+    let mut background_handles: Vec<thread::JoinHandle<()>> = Vec::new();
+    let mut pcap_tx = None;
+    if let Some(path) = opts.pcap.clone() {
+        match pcap::pcap_worker(path, cancel_token.clone()) {
+            Ok((h, tx)) => { background_handles.push(h); pcap_tx = Some(tx); },
+            Err(e) => log::error!("failed to start pcap worker: {}", e),
+        }
     }
 
-    let mut l4_handles: Option<JoinSet<()>> = None;
-    if opts.l4 {
-        let l4_cfg = Layer4Config {
-            listen_on: opts.l4_listen_on,
-            relay_to: opts.l4_relay_to,
-        };
-        l4_handles = Some(layer4::l4_worker(l4_cfg, cancel_token));
-    }
+    let mut file_cfg = opts.config.as_ref().and_then(load_config_file);
+
+    let (reload_tx, mut reload_rx) = mpsc::channel::<()>(1);
+    let _watcher = opts.config.as_ref().and_then(|path| {
+        match config::watch_for_changes(path.clone(), reload_tx.clone()) {
+            Ok(w) => Some(w),
+            Err(e) => { log::error!("failed to watch config file '{}': {}", path.display(), e); None },
+        }
+    });
 
-    // wait for workers
-    l2_handles.into_iter().for_each(|h| { let _ = h.join(); });
-    if let Some(tasks) = l4_handles {
-        tasks.join_all().await;
+    // Created once for the life of the process, independent of whether the
+    // tunnel is currently enabled, so toggling/editing `[tunnel]` on reload
+    // never requires rebuilding l2/l4 just to hand them a new sender.
+    let (tunnel_tx, tunnel_rx) = mpsc::channel::<Box<[u8]>>(8);
+    let tunnel_rx = Arc::new(tokio::sync::Mutex::new(tunnel_rx));
+
+    let mut workers = spawn_workers(&opts, file_cfg.as_ref(), &cancel_token, pcap_tx.clone(), tunnel_tx.clone(), tunnel_rx.clone());
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            Some(()) = reload_rx.recv() => {
+                // Editors commonly emit several modify events per save; wait
+                // briefly for the burst to settle before reloading.
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                while reload_rx.try_recv().is_ok() {}
+
+                log::info!("config file changed, checking for changed sections");
+                file_cfg = opts.config.as_ref().and_then(load_config_file);
+
+                let old = std::mem::replace(&mut workers, Workers::default());
+                workers = reload_workers(&opts, file_cfg.as_ref(), &cancel_token, pcap_tx.clone(), tunnel_tx.clone(), tunnel_rx.clone(), old).await;
+            },
+            else => break,
+        }
     }
-}
\ No newline at end of file
+
+    workers.shut_down().await;
+    background_handles.into_iter().for_each(|h| { let _ = h.join(); });
+}
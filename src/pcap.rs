@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use tokio_util::sync::CancellationToken;
+
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 128;
+
+/// A single packet queued for the pcap writer, either a raw `EthernetPacket`
+/// observed/relayed by `layer2` or a synthesized IP/UDP datagram from `layer4`.
+pub(crate) struct CapturedPacket {
+    pub(crate) linktype: u32,
+    pub(crate) data: Vec<u8>,
+}
+
+pub(crate) type PcapSender = SyncSender<CapturedPacket>;
+
+fn write_global_header(w: &mut impl Write, linktype: u32) -> std::io::Result<()> {
+    w.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    w.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+    w.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+    w.write_all(&0i32.to_le_bytes())?; // GMT to local correction
+    w.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+    w.write_all(&SNAPLEN.to_le_bytes())?;
+    w.write_all(&linktype.to_le_bytes())
+}
+
+fn write_packet_record(w: &mut impl Write, pkt: &[u8]) -> std::io::Result<()> {
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let caplen = pkt.len().min(SNAPLEN as usize) as u32;
+
+    w.write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+    w.write_all(&ts.subsec_micros().to_le_bytes())?;
+    w.write_all(&caplen.to_le_bytes())?;
+    w.write_all(&(pkt.len() as u32).to_le_bytes())?;
+    w.write_all(&pkt[..caplen as usize])
+}
+
+/// Classic pcap only supports one link type per file, so a capture path like
+/// `capture.pcap` is split per linktype into `capture.linktype-N.pcap`, one
+/// file per distinct linktype actually observed. This keeps `--l2 --l4
+/// --pcap` from silently discarding whichever layer didn't emit first.
+fn path_for_linktype(base: &str, linktype: u32) -> String {
+    let path = Path::new(base);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or(base);
+    let suffixed = format!("{}.linktype-{}", stem, linktype);
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => path.with_file_name(format!("{}.{}", suffixed, ext)).to_string_lossy().into_owned(),
+        None => path.with_file_name(suffixed).to_string_lossy().into_owned(),
+    }
+}
+
+fn open_writer(base: &str, linktype: u32) -> Result<BufWriter<File>> {
+    let path = path_for_linktype(base, linktype);
+    let file = File::create(&path).map_err(|e| anyhow!("unable to create pcap file '{}': {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+    write_global_header(&mut writer, linktype)?;
+    Ok(writer)
+}
+
+/// Starts the pcap writer thread and returns a sender that `layer2`/`layer4`
+/// can hand captured packets to. A plain `std::sync::mpsc` channel is used
+/// (rather than tokio's) so both the thread-based L2 workers and the async L4
+/// tasks can feed it the same way, via non-blocking `try_send`, keeping the
+/// hot RX/relay paths free of file I/O.
+///
+/// Each distinct linktype gets its own file (see `path_for_linktype`),
+/// created lazily the first time a packet of that linktype arrives.
+pub fn pcap_worker(path: String, token: CancellationToken) -> Result<(JoinHandle<()>, PcapSender)> {
+    let (tx, rx): (PcapSender, Receiver<CapturedPacket>) = sync_channel(64);
+
+    let h = std::thread::spawn(move || {
+        let mut writers: HashMap<u32, BufWriter<File>> = HashMap::new();
+
+        loop {
+            if token.is_cancelled() { log::trace!("[pcap] exit"); break; }
+
+            let pkt = match rx.recv_timeout(Duration::from_millis(200)) {
+                Ok(p) => p,
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+
+            let writer = match writers.entry(pkt.linktype) {
+                std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                std::collections::hash_map::Entry::Vacant(e) => {
+                    match open_writer(&path, pkt.linktype) {
+                        Ok(w) => e.insert(w),
+                        Err(err) => { log::error!("[pcap] {}", err); continue; },
+                    }
+                },
+            };
+
+            if let Err(e) = write_packet_record(writer, &pkt.data) {
+                log::error!("[pcap] failed to write packet: {}", e);
+                continue;
+            }
+            writer.flush().ok();
+        }
+    });
+
+    Ok((h, tx))
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+    Key,
+    Nonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Deserializer};
+
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::common;
+use crate::layer4::WolMessage;
+
+/// Magic value identifying a tunnel datagram, sent as plaintext header and
+/// additional authenticated data so mismatched versions/protocols are rejected early.
+const TUNNEL_MAGIC: u32 = 0x574f_4c54; // "WOLT"
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = 4 + 8 + NONCE_LEN;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+const PEER_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Channel `layer2`/`layer4` use to hand the tunnel worker magic packets they
+/// observed locally, so it can encrypt and forward them to every peer. Created
+/// once in `main` and handed to every worker regardless of whether the tunnel
+/// is currently enabled, so a config reload that only touches `[tunnel]` (or
+/// toggles it on/off) never has to tear down and rebuild l2/l4 just to hand
+/// them a new sender.
+pub(crate) type TunnelSender = mpsc::Sender<Box<[u8]>>;
+
+/// The receiving half of [`TunnelSender`]'s channel, shared so it can outlive
+/// any single `tunnel_worker` invocation: on a reload where only `[tunnel]`
+/// changed, the old egress task is cancelled and a new one locks this same
+/// receiver, rather than a fresh channel being created and `layer2`/`layer4`
+/// needing to be rebuilt to pick up a new sender.
+pub(crate) type TunnelReceiver = std::sync::Arc<tokio::sync::Mutex<mpsc::Receiver<Box<[u8]>>>>;
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TunnelConfig {
+    pub listen_on: SocketAddr,
+    pub peers: Vec<SocketAddr>,
+    pub group_id: u64,
+    /// 64 hex characters (32 bytes) in a config file, same format as `--tunnel-psk`.
+    #[serde(deserialize_with = "deserialize_psk")]
+    pub psk: [u8; 32],
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            listen_on: SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 0),
+            peers: Vec::new(),
+            group_id: 0,
+            psk: [0u8; 32],
+        }
+    }
+}
+
+/// Parses a pre-shared key given as 64 hex characters (32 bytes), the format
+/// used by both `--tunnel-psk` and a config file's `psk` field.
+pub fn parse_psk_hex(hex: &str) -> Result<[u8; 32]> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| anyhow!("tunnel-psk must be valid hex"))?;
+
+    bytes.try_into().map_err(|_| anyhow!("tunnel-psk must decode to exactly 32 bytes"))
+}
+
+fn deserialize_psk<'de, D>(deserializer: D) -> std::result::Result<[u8; 32], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hex = String::deserialize(deserializer)?;
+    parse_psk_hex(&hex).map_err(serde::de::Error::custom)
+}
+
+struct PeerList {
+    /// configured peers are never forgotten, learned ones age out via `housekeep`
+    peers: HashMap<SocketAddr, (bool, Instant)>,
+}
+
+impl PeerList {
+    fn new(configured: &[SocketAddr]) -> Self {
+        let now = Instant::now();
+        let peers = configured.iter()
+            .map(|addr| (*addr, (true, now)))
+            .collect();
+
+        PeerList { peers }
+    }
+
+    fn learn(&mut self, addr: SocketAddr) {
+        self.peers.entry(addr)
+            .and_modify(|(_, seen)| *seen = Instant::now())
+            .or_insert((false, Instant::now()));
+    }
+
+    fn housekeep(&mut self) {
+        self.peers.retain(|_, (configured, seen)| *configured || seen.elapsed() < PEER_TIMEOUT);
+    }
+
+    fn addrs(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers.keys()
+    }
+}
+
+fn header_bytes(group_id: u64, nonce: &[u8; NONCE_LEN]) -> [u8; HEADER_LEN] {
+    let mut buf = [0u8; HEADER_LEN];
+    buf[0..4].copy_from_slice(&TUNNEL_MAGIC.to_be_bytes());
+    buf[4..12].copy_from_slice(&group_id.to_be_bytes());
+    buf[12..HEADER_LEN].copy_from_slice(nonce);
+    buf
+}
+
+fn encrypt_datagram(cipher: &ChaCha20Poly1305, group_id: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+
+    let header = header_bytes(group_id, &nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: payload, aad: &header })
+        .map_err(|_| anyhow!("failed to encrypt tunnel datagram"))?;
+
+    let mut datagram = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    datagram.extend_from_slice(&header);
+    datagram.extend_from_slice(&ciphertext);
+    Ok(datagram)
+}
+
+/// Authenticates and decrypts a received datagram, rejecting it if the header
+/// magic/group_id don't match or the AEAD tag doesn't verify.
+fn decrypt_datagram(cipher: &ChaCha20Poly1305, group_id: u64, datagram: &[u8]) -> Option<Vec<u8>> {
+    if datagram.len() < HEADER_LEN { return None; }
+
+    let magic = u32::from_be_bytes(datagram[0..4].try_into().ok()?);
+    let peer_group_id = u64::from_be_bytes(datagram[4..12].try_into().ok()?);
+    if magic != TUNNEL_MAGIC || peer_group_id != group_id {
+        return None;
+    }
+
+    let header = &datagram[0..HEADER_LEN];
+    let nonce = Nonce::from_slice(&datagram[12..HEADER_LEN]);
+
+    cipher.decrypt(nonce, Payload { msg: &datagram[HEADER_LEN..], aad: header }).ok()
+}
+
+pub fn tunnel_worker(
+    cfg: TunnelConfig,
+    token: CancellationToken,
+    l4_tx: mpsc::Sender<WolMessage>,
+    egress_rx: TunnelReceiver,
+) -> Result<JoinSet<()>> {
+    let mut tasks: JoinSet<()> = JoinSet::new();
+
+    let key = Key::from_slice(&cfg.psk);
+    let cipher = ChaCha20Poly1305::new(key);
+    let group_id = cfg.group_id;
+    let listen_on = cfg.listen_on;
+    let peers = PeerList::new(&cfg.peers);
+
+    let sock = std::net::UdpSocket::bind(listen_on)
+        .map_err(|e| anyhow!("unable to bind tunnel socket: {}", e))?;
+    sock.set_nonblocking(true)?;
+    let sock = UdpSocket::from_std(sock)?;
+    let sock = std::sync::Arc::new(sock);
+
+    let peers = std::sync::Arc::new(tokio::sync::Mutex::new(peers));
+
+    // RX: receive and decrypt remote datagrams, feed the relay channel used by l4_worker
+    {
+        let sock = sock.clone();
+        let cipher = cipher.clone();
+        let token = token.clone();
+        let peers = peers.clone();
+
+        tasks.spawn(async move {
+            let mut buf = [0u8; 256];
+            loop {
+                if token.is_cancelled() { log::trace!("[tunnel][listener] exit"); break; }
+
+                let (len, from) = match tokio::time::timeout(
+                    Duration::from_millis(100),
+                    sock.recv_from(&mut buf)
+                ).await {
+                    Ok(Ok(res)) => res,
+                    Ok(Err(_)) | Err(_) => continue,
+                };
+
+                let payload = match decrypt_datagram(&cipher, group_id, &buf[..len]) {
+                    Some(p) => p,
+                    None => { log::debug!("[tunnel] dropping unauthenticated datagram from {}", from); continue; }
+                };
+
+                // Any authenticated datagram, including empty keepalives, counts
+                // as a live sign from the peer and refreshes its timeout.
+                peers.lock().await.learn(from);
+
+                if !common::check_wol_payload(&payload) {
+                    log::debug!("[tunnel] dropping non-WOL payload from {}", from);
+                    continue;
+                }
+
+                log::debug!("[tunnel] received WakeOnLan packet from peer {}", from);
+                l4_tx.send(WolMessage {
+                    src: from,
+                    target: common::wol_payload_get_target_mac(&payload),
+                    msg: Box::from(payload.as_slice()),
+                    via_tunnel: true,
+                }).await.ok();
+            }
+        });
+    }
+
+    // Egress: encrypt magic packets observed locally by layer2/layer4 and
+    // forward them to every peer, so a wake seen on this site is relayed to
+    // the others. Messages l4_worker re-injects after receiving them from
+    // this same tunnel are marked `via_tunnel` and never reach this channel
+    // (see l4_worker's relay loop), so a packet can't bounce back out here.
+    {
+        let sock = sock.clone();
+        let cipher = cipher.clone();
+        let token = token.clone();
+        let peers = peers.clone();
+
+        tasks.spawn(async move {
+            // Held for as long as this task runs, so only one tunnel_worker
+            // at a time can be draining the shared channel; a respawned
+            // worker re-locks it once this one exits on cancellation.
+            let mut egress_rx = egress_rx.lock().await;
+
+            loop {
+                if token.is_cancelled() { log::trace!("[tunnel][egress] exit"); break; }
+
+                let payload = match tokio::time::timeout(Duration::from_millis(100), egress_rx.recv()).await {
+                    Ok(Some(p)) => p,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                };
+
+                let datagram = match encrypt_datagram(&cipher, group_id, &payload) {
+                    Ok(d) => d,
+                    Err(e) => { log::warn!("[tunnel] failed to encrypt outgoing datagram: {}", e); continue; }
+                };
+
+                for addr in peers.lock().await.addrs() {
+                    sock.send_to(&datagram, addr).await.ok();
+                }
+            }
+        });
+    }
+
+    // Keepalive: periodically ping every known peer and age out learned ones.
+    {
+        let sock = sock.clone();
+        let cipher = cipher.clone();
+        let token = token.clone();
+        let peers = peers.clone();
+
+        tasks.spawn(async move {
+            loop {
+                if token.is_cancelled() { log::trace!("[tunnel][keepalive] exit"); break; }
+
+                tokio::time::sleep(RECONNECT_INTERVAL).await;
+
+                let mut peers = peers.lock().await;
+                peers.housekeep();
+
+                if let Ok(keepalive) = encrypt_datagram(&cipher, group_id, &[]) {
+                    for addr in peers.addrs() {
+                        sock.send_to(&keepalive, addr).await.ok();
+                    }
+                }
+            }
+        });
+    }
+
+    Ok(tasks)
+}